@@ -0,0 +1,533 @@
+use std::io;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::ImportError;
+use crate::vty::{HlrSession, SubscriberInfo, VtyResponse};
+
+/// One SIM queued up to be imported, either on its own or as part of a
+/// [`HlrBackend::import_batch`] batch.
+pub struct PendingImport<'a> {
+    pub imsi: &'a str,
+    pub default_msisdn: u64,
+    pub ki: &'a str,
+    pub opc: &'a str,
+}
+
+/// What happened when importing a single [`PendingImport`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordOutcome {
+    /// The subscriber already existed in the HLR; nothing beyond the `show`
+    /// was sent.
+    AlreadyPresent,
+    Created,
+}
+
+/// The HLR operations the importer needs, abstracted away from the VTY
+/// session so the import logic can be driven against an in-memory mock in
+/// tests instead of a live OsmoHLR on `127.0.0.1:4258`.
+pub trait HlrBackend {
+    fn show(&mut self, imsi: &str) -> io::Result<VtyResponse>;
+    fn create(&mut self, imsi: &str) -> io::Result<VtyResponse>;
+    fn update_msisdn(&mut self, imsi: &str, msisdn: u64) -> io::Result<VtyResponse>;
+    fn update_aud3g(&mut self, imsi: &str, ki: &str, opc: &str) -> io::Result<VtyResponse>;
+
+    /// Run the show/create/update-msisdn/update-aud3g sequence for each
+    /// record in `batch`. The default implementation just calls
+    /// [`import_one`] in a loop; [`HlrSession`] overrides this to pipeline
+    /// the underlying VTY commands instead of round-tripping one at a time.
+    fn import_batch(
+        &mut self,
+        batch: &[PendingImport<'_>],
+    ) -> Vec<Result<RecordOutcome, ImportError>>
+    where
+        Self: Sized,
+    {
+        batch
+            .iter()
+            .map(|r| import_one(self, r.imsi, r.default_msisdn, r.ki, r.opc))
+            .collect()
+    }
+}
+
+impl HlrBackend for HlrSession {
+    fn show(&mut self, imsi: &str) -> io::Result<VtyResponse> {
+        self.command_parsed(&format!("subscriber imsi {imsi} show"))
+    }
+
+    fn create(&mut self, imsi: &str) -> io::Result<VtyResponse> {
+        self.command_parsed(&format!("subscriber imsi {imsi} create"))
+    }
+
+    fn update_msisdn(&mut self, imsi: &str, msisdn: u64) -> io::Result<VtyResponse> {
+        self.command_parsed(&format!("subscriber imsi {imsi} update msisdn {msisdn}"))
+    }
+
+    fn update_aud3g(&mut self, imsi: &str, ki: &str, opc: &str) -> io::Result<VtyResponse> {
+        // KI/OPC are in this command string, so it gets zeroized on drop
+        // just like the `KeysRecord` they came from.
+        let cmd = Zeroizing::new(format!(
+            "subscriber imsi {imsi} update aud3g milenage k {ki} opc {opc}"
+        ));
+        self.command_parsed(&cmd)
+    }
+
+    fn import_batch(
+        &mut self,
+        batch: &[PendingImport<'_>],
+    ) -> Vec<Result<RecordOutcome, ImportError>> {
+        let n = batch.len();
+        let mut results: Vec<Option<Result<RecordOutcome, ImportError>>> =
+            (0..n).map(|_| None).collect();
+
+        let show_cmds: Vec<String> = batch
+            .iter()
+            .map(|r| format!("subscriber imsi {} show", r.imsi))
+            .collect();
+        let show_responses = match self.command_batch_parsed(&show_cmds) {
+            Ok(r) => r,
+            Err(e) => return fill_batch_error(n, &e),
+        };
+
+        let mut pending: Vec<usize> = Vec::new();
+        for (i, resp) in show_responses.into_iter().enumerate() {
+            match classify_show(resp) {
+                Ok(None) => pending.push(i),
+                Ok(Some(outcome)) => results[i] = Some(Ok(outcome)),
+                Err(e) => results[i] = Some(Err(e)),
+            }
+        }
+
+        // Two records for the same IMSI in one batch both see `show ->
+        // NoSubscriber` (all shows are sent before any create), so the
+        // second one's `create` races the first and gets `AlreadyExists`
+        // rather than `Created`. The same thing happens if the connection
+        // drops mid-batch: `command_batch` reconnects and resends every
+        // command in the batch, so a `create` that already landed comes
+        // back as `AlreadyExists` too. Either way the subscriber now
+        // exists, so treat `AlreadyExists` the same as `Created` and carry
+        // on to the update stages — the alternative (stopping here) would
+        // leave a reconnect-resent record with no MSISDN or KI/OPC while
+        // still reporting success.
+        pending = self.run_batch_stage(
+            &mut results,
+            pending,
+            "create",
+            |r| format!("subscriber imsi {} create", r.imsi),
+            batch,
+            |resp| matches!(resp, VtyResponse::Created | VtyResponse::AlreadyExists),
+        );
+
+        pending = self.run_batch_stage(
+            &mut results,
+            pending,
+            "update msisdn",
+            |r| {
+                format!(
+                    "subscriber imsi {} update msisdn {}",
+                    r.imsi, r.default_msisdn
+                )
+            },
+            batch,
+            |resp| matches!(resp, VtyResponse::Updated),
+        );
+
+        pending = self.run_batch_stage(
+            &mut results,
+            pending,
+            "update aud3g",
+            |r| {
+                format!(
+                    "subscriber imsi {} update aud3g milenage k {} opc {}",
+                    r.imsi, r.ki, r.opc
+                )
+            },
+            batch,
+            |resp| matches!(resp, VtyResponse::Ok),
+        );
+
+        for i in pending {
+            results[i] = Some(Ok(RecordOutcome::Created));
+        }
+
+        results
+            .into_iter()
+            .map(|r| r.expect("every index filled"))
+            .collect()
+    }
+}
+
+impl HlrSession {
+    /// Send a batched command for each still-`pending` index, mark any that
+    /// didn't get the `wanted` response as failed, and return the indices
+    /// that are still pending for the next stage.
+    fn run_batch_stage(
+        &mut self,
+        results: &mut [Option<Result<RecordOutcome, ImportError>>],
+        pending: Vec<usize>,
+        command_name: &str,
+        build_cmd: impl Fn(&PendingImport<'_>) -> String,
+        batch: &[PendingImport<'_>],
+        wanted: impl Fn(&VtyResponse) -> bool,
+    ) -> Vec<usize> {
+        if pending.is_empty() {
+            return pending;
+        }
+
+        // `build_cmd` may interpolate KI/OPC (the "update aud3g" stage
+        // does), so scrub the formatted commands once they've been sent
+        // rather than leaving the secrets sitting in this `Vec<String>`.
+        let mut cmds: Vec<String> = pending.iter().map(|&i| build_cmd(&batch[i])).collect();
+        let responses = match self.command_batch_parsed(&cmds) {
+            Ok(r) => {
+                cmds.zeroize();
+                r
+            }
+            Err(e) => {
+                cmds.zeroize();
+                let err_text = e.to_string();
+                for &i in &pending {
+                    results[i] = Some(Err(ImportError::Io(io::Error::new(
+                        e.kind(),
+                        err_text.clone(),
+                    ))));
+                }
+                return Vec::new();
+            }
+        };
+
+        let mut still_pending = Vec::new();
+        for (&i, resp) in pending.iter().zip(responses) {
+            if wanted(&resp) {
+                still_pending.push(i);
+            } else {
+                results[i] = Some(Err(ImportError::UnexpectedResponse {
+                    command: command_name.to_string(),
+                    response: format!("{resp:?}"),
+                }));
+            }
+        }
+        still_pending
+    }
+}
+
+fn fill_batch_error(n: usize, e: &io::Error) -> Vec<Result<RecordOutcome, ImportError>> {
+    let err_text = e.to_string();
+    (0..n)
+        .map(|_| Err(ImportError::Io(io::Error::new(e.kind(), err_text.clone()))))
+        .collect()
+}
+
+/// Classify a `show` response: `Ok(None)` means the subscriber doesn't
+/// exist yet and the import should proceed, `Ok(Some(_))` short-circuits
+/// the rest of the import with that outcome, and `Err` means the response
+/// was a VTY error or otherwise didn't parse as a subscriber dump — that's
+/// a per-record failure, not "already present".
+fn classify_show(response: VtyResponse) -> Result<Option<RecordOutcome>, ImportError> {
+    match response {
+        VtyResponse::NoSubscriber => Ok(None),
+        VtyResponse::Shown(_) => Ok(Some(RecordOutcome::AlreadyPresent)),
+        other => Err(ImportError::UnexpectedResponse {
+            command: "show".to_string(),
+            response: format!("{other:?}"),
+        }),
+    }
+}
+
+/// Run the show/create/update-msisdn/update-aud3g sequence for one SIM.
+pub fn import_one(
+    hlr: &mut impl HlrBackend,
+    imsi: &str,
+    default_msisdn: u64,
+    ki: &str,
+    opc: &str,
+) -> Result<RecordOutcome, ImportError> {
+    if let Some(outcome) = classify_show(hlr.show(imsi)?)? {
+        return Ok(outcome);
+    }
+
+    expect_response(hlr.create(imsi)?, "create", |r| {
+        matches!(r, VtyResponse::Created)
+    })?;
+    expect_response(
+        hlr.update_msisdn(imsi, default_msisdn)?,
+        "update msisdn",
+        |r| matches!(r, VtyResponse::Updated),
+    )?;
+    expect_response(hlr.update_aud3g(imsi, ki, opc)?, "update aud3g", |r| {
+        matches!(r, VtyResponse::Ok)
+    })?;
+
+    Ok(RecordOutcome::Created)
+}
+
+fn expect_response(
+    response: VtyResponse,
+    command: &str,
+    wanted: impl Fn(&VtyResponse) -> bool,
+) -> Result<(), ImportError> {
+    if wanted(&response) {
+        Ok(())
+    } else {
+        Err(ImportError::UnexpectedResponse {
+            command: command.to_string(),
+            response: format!("{response:?}"),
+        })
+    }
+}
+
+/// An in-memory [`HlrBackend`] for driving the import logic in tests
+/// without a live OsmoHLR. Kept `pub(crate)` (rather than `#[cfg(test)]`
+/// private to this module) so other modules' tests can use it too.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// In-memory HLR used by tests: tracks subscriber state locally and lets
+    /// a test script a raw (possibly truncated or non-UTF-8) byte response
+    /// for a specific command, instead of driving a real OsmoHLR.
+    #[derive(Default)]
+    pub(crate) struct MockHlr {
+        pub(crate) commands: Vec<String>,
+        subscribers: HashMap<String, (Option<u64>, Option<(String, String)>)>,
+        scripted_raw: HashMap<String, Vec<u8>>,
+    }
+
+    impl MockHlr {
+        pub(crate) fn new() -> Self {
+            MockHlr::default()
+        }
+
+        /// Pre-populate a subscriber as if it had already been created.
+        pub(crate) fn seed_existing(&mut self, imsi: &str) {
+            self.subscribers.insert(imsi.to_string(), (None, None));
+        }
+
+        /// Make the next call matching `command` return this raw byte
+        /// buffer instead of a normal response, to simulate a truncated
+        /// read or a non-UTF-8 byte sequence coming back from the HLR.
+        pub(crate) fn script_raw_response(&mut self, command: &str, raw: Vec<u8>) {
+            self.scripted_raw.insert(command.to_string(), raw);
+        }
+
+        /// Take over with the scripted raw response for `command`, if one was
+        /// set up: valid UTF-8 comes back as `VtyResponse::Error` holding
+        /// the text, anything else as an `InvalidData` I/O error. Callers
+        /// record the command in `commands` themselves.
+        fn take_scripted(&mut self, command: &str) -> Option<io::Result<VtyResponse>> {
+            self.scripted_raw.remove(command).map(|raw| {
+                String::from_utf8(raw)
+                    .map(VtyResponse::Error)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            })
+        }
+    }
+
+    impl HlrBackend for MockHlr {
+        fn show(&mut self, imsi: &str) -> io::Result<VtyResponse> {
+            let command = format!("subscriber imsi {imsi} show");
+            self.commands.push(command.clone());
+            if let Some(res) = self.take_scripted(&command) {
+                return res;
+            }
+            Ok(match self.subscribers.get(imsi) {
+                Some((msisdn, auth)) => VtyResponse::Shown(SubscriberInfo {
+                    imsi: imsi.to_string(),
+                    msisdn: msisdn.map(|m| m.to_string()),
+                    auth_algo: auth.as_ref().map(|_| "MILENAGE".to_string()),
+                }),
+                None => VtyResponse::NoSubscriber,
+            })
+        }
+
+        fn create(&mut self, imsi: &str) -> io::Result<VtyResponse> {
+            let command = format!("subscriber imsi {imsi} create");
+            self.commands.push(command.clone());
+            if let Some(res) = self.take_scripted(&command) {
+                return res;
+            }
+            if self.subscribers.contains_key(imsi) {
+                return Ok(VtyResponse::AlreadyExists);
+            }
+            self.subscribers.insert(imsi.to_string(), (None, None));
+            Ok(VtyResponse::Created)
+        }
+
+        fn update_msisdn(&mut self, imsi: &str, msisdn: u64) -> io::Result<VtyResponse> {
+            let command = format!("subscriber imsi {imsi} update msisdn {msisdn}");
+            self.commands.push(command.clone());
+            if let Some(res) = self.take_scripted(&command) {
+                return res;
+            }
+            match self.subscribers.get_mut(imsi) {
+                Some(entry) => {
+                    entry.0 = Some(msisdn);
+                    Ok(VtyResponse::Updated)
+                }
+                None => Ok(VtyResponse::NoSubscriber),
+            }
+        }
+
+        fn update_aud3g(&mut self, imsi: &str, ki: &str, opc: &str) -> io::Result<VtyResponse> {
+            let command = format!("subscriber imsi {imsi} update aud3g milenage k {ki} opc {opc}");
+            self.commands.push(command.clone());
+            if let Some(res) = self.take_scripted(&command) {
+                return res;
+            }
+            match self.subscribers.get_mut(imsi) {
+                Some(entry) => {
+                    entry.1 = Some((ki.to_string(), opc.to_string()));
+                    Ok(VtyResponse::Ok)
+                }
+                None => Ok(VtyResponse::NoSubscriber),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockHlr;
+    use super::*;
+
+    #[test]
+    fn create_then_show_reports_subscriber() {
+        let mut hlr = MockHlr::new();
+        assert_eq!(
+            hlr.show("901700000000001").unwrap(),
+            VtyResponse::NoSubscriber
+        );
+        assert_eq!(hlr.create("901700000000001").unwrap(), VtyResponse::Created);
+        hlr.update_msisdn("901700000000001", 90400001).unwrap();
+
+        match hlr.show("901700000000001").unwrap() {
+            VtyResponse::Shown(info) => assert_eq!(info.msisdn.as_deref(), Some("90400001")),
+            other => panic!("expected Shown, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn duplicate_create_reports_already_exists() {
+        let mut hlr = MockHlr::new();
+        hlr.seed_existing("901700000000002");
+        assert_eq!(
+            hlr.create("901700000000002").unwrap(),
+            VtyResponse::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn truncated_response_is_an_error_not_a_panic() {
+        let mut hlr = MockHlr::new();
+        hlr.script_raw_response(
+            "subscriber imsi 901700000000003 create",
+            b"% Creat".to_vec(),
+        );
+        let res = hlr.create("901700000000003").unwrap();
+        assert!(matches!(res, VtyResponse::Error(_)));
+    }
+
+    #[test]
+    fn invalid_utf8_response_is_a_graceful_error() {
+        let mut hlr = MockHlr::new();
+        hlr.script_raw_response(
+            "subscriber imsi 901700000000004 create",
+            vec![0xff, 0xfe, 0xfd],
+        );
+        let err = hlr.create("901700000000004").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn command_sequence_is_recorded_for_assertions() {
+        let mut hlr = MockHlr::new();
+        hlr.show("901700000000005").unwrap();
+        hlr.create("901700000000005").unwrap();
+        assert_eq!(
+            hlr.commands,
+            vec![
+                "subscriber imsi 901700000000005 show".to_string(),
+                "subscriber imsi 901700000000005 create".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn creates_new_subscriber() {
+        let mut hlr = MockHlr::new();
+        let outcome = import_one(&mut hlr, "901700000000001", 90400001, "ki", "opc").unwrap();
+        assert_eq!(outcome, RecordOutcome::Created);
+    }
+
+    #[test]
+    fn skips_subscriber_that_already_exists() {
+        let mut hlr = MockHlr::new();
+        hlr.seed_existing("901700000000002");
+        let outcome = import_one(&mut hlr, "901700000000002", 90400002, "ki", "opc").unwrap();
+        assert_eq!(outcome, RecordOutcome::AlreadyPresent);
+    }
+
+    #[test]
+    fn malformed_show_response_is_an_error_not_already_present() {
+        let mut hlr = MockHlr::new();
+        hlr.script_raw_response(
+            "subscriber imsi 901700000000008 show",
+            b"% Garbled".to_vec(),
+        );
+        let err = import_one(&mut hlr, "901700000000008", 90400008, "ki", "opc").unwrap_err();
+        assert!(matches!(err, ImportError::UnexpectedResponse { .. }));
+    }
+
+    #[test]
+    fn malformed_create_response_is_an_error_not_a_panic() {
+        let mut hlr = MockHlr::new();
+        hlr.script_raw_response(
+            "subscriber imsi 901700000000003 create",
+            b"% Creat".to_vec(),
+        );
+        let err = import_one(&mut hlr, "901700000000003", 90400003, "ki", "opc").unwrap_err();
+        assert!(matches!(err, ImportError::UnexpectedResponse { .. }));
+    }
+
+    #[test]
+    fn invalid_utf8_response_is_an_error_not_a_panic() {
+        let mut hlr = MockHlr::new();
+        hlr.script_raw_response(
+            "subscriber imsi 901700000000004 create",
+            vec![0xff, 0xfe, 0xfd],
+        );
+        let err = import_one(&mut hlr, "901700000000004", 90400004, "ki", "opc").unwrap_err();
+        assert!(matches!(err, ImportError::Io(_)));
+    }
+
+    #[test]
+    fn import_batch_default_impl_matches_sequential_outcomes() {
+        let mut hlr = MockHlr::new();
+        hlr.seed_existing("901700000000006");
+        let batch = vec![
+            PendingImport {
+                imsi: "901700000000006",
+                default_msisdn: 90400006,
+                ki: "ki",
+                opc: "opc",
+            },
+            PendingImport {
+                imsi: "901700000000007",
+                default_msisdn: 90400007,
+                ki: "ki",
+                opc: "opc",
+            },
+        ];
+
+        let outcomes: Vec<RecordOutcome> = hlr
+            .import_batch(&batch)
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![RecordOutcome::AlreadyPresent, RecordOutcome::Created]
+        );
+    }
+}
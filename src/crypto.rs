@@ -0,0 +1,129 @@
+use std::fs;
+use std::io;
+
+use aes_gcm_siv::aead::{Aead, KeyInit};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Load the AES-256-GCM-SIV key used to encrypt/decrypt KI/OPC secrets and,
+/// when `--encrypt-defaults` is set, the IMSI/MSISDN mapping. Read from the
+/// file named by `HLR_IMPORTER_KEY_FILE`, falling back to the hex-encoded
+/// `HLR_IMPORTER_KEY` env var.
+pub fn load_key() -> io::Result<Zeroizing<[u8; KEY_LEN]>> {
+    let raw = if let Ok(path) = std::env::var("HLR_IMPORTER_KEY_FILE") {
+        Zeroizing::new(fs::read(path)?)
+    } else if let Ok(hex) = std::env::var("HLR_IMPORTER_KEY") {
+        Zeroizing::new(
+            decode_hex(&hex).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        )
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no encryption key: set HLR_IMPORTER_KEY_FILE or HLR_IMPORTER_KEY",
+        ));
+    };
+
+    if raw.len() != KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("encryption key must be {KEY_LEN} bytes, got {}", raw.len()),
+        ));
+    }
+
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&raw);
+    Ok(Zeroizing::new(key))
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if s.len() % 2 != 0 {
+        return Err("hex key must have an even number of digits".to_string());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Encrypt `plaintext` under `key` with a fresh random nonce, returning
+/// `nonce || ciphertext`.
+pub fn encrypt(key: &[u8; KEY_LEN], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = Aes256GcmSiv::new(key.into());
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce_bytes);
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a `nonce || ciphertext` buffer produced by [`encrypt`]. The
+/// returned plaintext is zeroized on drop since it may contain KI/OPC
+/// secrets or the IMSI/MSISDN mapping.
+pub fn decrypt(key: &[u8; KEY_LEN], data: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+    if data.len() < NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ciphertext shorter than nonce",
+        ));
+    }
+    let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = Aes256GcmSiv::new(key.into());
+    let nonce = Nonce::from_slice(nonce_bytes);
+
+    let plaintext = cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Which of the importer's file inputs/outputs should go through
+/// [`encrypt`]/[`decrypt`], set via `--encrypted-input`/`--encrypt-defaults`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncryptionOptions {
+    pub encrypted_input: bool,
+    pub encrypt_defaults: bool,
+}
+
+impl EncryptionOptions {
+    /// Consume `--encrypted-input` and `--encrypt-defaults` flags out of
+    /// `args`, leaving everything else for the caller to interpret.
+    pub fn from_args(args: &mut Vec<String>) -> Self {
+        let mut opts = EncryptionOptions::default();
+        let mut i = 0;
+        while i < args.len() {
+            let consumed = match args[i].as_str() {
+                "--encrypted-input" => {
+                    opts.encrypted_input = true;
+                    true
+                }
+                "--encrypt-defaults" => {
+                    opts.encrypt_defaults = true;
+                    true
+                }
+                _ => false,
+            };
+
+            if consumed {
+                args.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        opts
+    }
+
+    pub fn any(&self) -> bool {
+        self.encrypted_input || self.encrypt_defaults
+    }
+}
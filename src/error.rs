@@ -0,0 +1,37 @@
+use std::fmt;
+use std::io;
+
+/// Why a single record (or the defaults file) failed to import, in place of
+/// the `std::process::exit(2/3/4)` calls this replaces.
+#[derive(Debug)]
+pub enum ImportError {
+    Csv(csv::Error),
+    Io(io::Error),
+    UnexpectedResponse { command: String, response: String },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Csv(e) => write!(f, "could not deserialize CSV record: {e}"),
+            ImportError::Io(e) => write!(f, "I/O error: {e}"),
+            ImportError::UnexpectedResponse { command, response } => {
+                write!(f, "unexpected HLR response to `{command}`: {response}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<csv::Error> for ImportError {
+    fn from(e: csv::Error) -> Self {
+        ImportError::Csv(e)
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
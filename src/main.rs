@@ -1,11 +1,20 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write, self};
-use std::net::TcpStream;
-use std::collections::HashMap;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+mod backend;
+mod crypto;
+mod error;
+mod vty;
+use backend::{HlrBackend, PendingImport, RecordOutcome};
+use crypto::EncryptionOptions;
+use error::ImportError;
+use vty::{HlrSession, VtyConfig};
 
 static DEFAULTS_PATH: &str = "./defaults.csv";
-static OSMO_HLR_ADDRESS: &str = "127.0.0.1:4258";
+static REJECTS_PATH: &str = "./rejects.csv";
 
 #[derive(Deserialize, Debug, Clone)]
 struct DefaultsRecord {
@@ -15,9 +24,12 @@ struct DefaultsRecord {
     default_msisdn: u64,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// The KI/OPC fields carry Milenage SIM secrets, so they're zeroized as soon
+/// as this record is dropped (i.e. right after being sent to the HLR).
+#[derive(Deserialize, Debug, Clone, Zeroize, ZeroizeOnDrop)]
 struct KeysRecord {
     #[serde(rename = "IMSI")]
+    #[zeroize(skip)]
     imsi: String,
     #[serde(rename = "KI")]
     ki: String,
@@ -25,80 +37,386 @@ struct KeysRecord {
     opc: String,
 }
 
-/// Read the default MSISDN file.
+/// Read the default MSISDN file, transparently decrypting it first if
+/// `key` is set (i.e. `--encrypt-defaults` was passed).
 /// Returns a mapping from IMSI to default MSISDN, and the latest default MSISDN observed.
-fn read_defaults(f: &mut File) -> io::Result<(HashMap<String, u64>, u64)> {
-    let mut reader = csv::Reader::from_reader(f);
+fn read_defaults(
+    f: &mut File,
+    key: Option<&[u8; crypto::KEY_LEN]>,
+) -> Result<(HashMap<String, u64>, u64), ImportError> {
+    let raw = match key {
+        None => None,
+        Some(key) => {
+            let mut ciphertext = Vec::new();
+            io::Read::read_to_end(f, &mut ciphertext)?;
+            if ciphertext.is_empty() {
+                None
+            } else {
+                Some(crypto::decrypt(key, &ciphertext)?)
+            }
+        }
+    };
+
     let mut imsis = HashMap::new();
     let mut biggest = 904_00000;
+
+    let mut reader = match &raw {
+        Some(plaintext) => csv::Reader::from_reader(plaintext.as_slice()),
+        None => csv::Reader::from_reader(&*f),
+    };
     for res in reader.deserialize() {
-        let record: DefaultsRecord = match res {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("fatal: could not deserialize record in defaults file!");
-                eprintln!("       error: {e}");
-                std::process::exit(3);
-            }
-        };
+        let record: DefaultsRecord = res?;
         imsis.insert(record.imsi, record.default_msisdn);
         biggest = std::cmp::max(biggest, record.default_msisdn);
     }
     Ok((imsis, biggest))
 }
 
-fn open_csv(path: &str) -> io::Result<csv::Reader<File>> {
-    let file = File::open(path)?;
-    Ok(csv::Reader::from_reader(file))
+/// Rewrite the (encrypted) defaults file from scratch with the current
+/// IMSI/MSISDN mapping. AES-GCM-SIV ciphertexts can't be appended to, so
+/// unlike the plaintext path this re-encrypts the whole mapping on every
+/// new default; that's fine at this tool's scale (thousands, not millions,
+/// of SIMs) and it's still written atomically via a temp file + rename.
+fn write_encrypted_defaults(
+    path: &str,
+    key: &[u8; crypto::KEY_LEN],
+    imsis: &HashMap<String, u64>,
+) -> io::Result<()> {
+    let mut plaintext = Vec::new();
+    {
+        let mut writer = csv::Writer::from_writer(&mut plaintext);
+        writer.write_record(["IMSI", "DefaultMSISDN"])?;
+        for (imsi, msisdn) in imsis {
+            writer.write_record([imsi.as_str(), &msisdn.to_string()])?;
+        }
+        writer.flush()?;
+    }
+
+    let ciphertext = crypto::encrypt(key, &plaintext)?;
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, &ciphertext)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Open a keys CSV for reading, transparently decrypting it first if `key`
+/// is set (i.e. `--encrypted-input` was passed). The decrypted plaintext is
+/// never written to disk.
+///
+/// The whole file's worth of KI/OPC secrets sit in the `Cursor` for the
+/// life of the reader, so it keeps the `Zeroizing` wrapper `decrypt`
+/// returns rather than copying out a plain `Vec<u8>`; this is still only a
+/// partial mitigation, since `csv`'s own internal line buffers retain
+/// their own unzeroized copies of whatever they've read.
+fn open_keys_csv(
+    path: &str,
+    key: Option<&[u8; crypto::KEY_LEN]>,
+) -> io::Result<csv::Reader<Box<dyn io::Read>>> {
+    match key {
+        None => Ok(csv::Reader::from_reader(Box::new(File::open(path)?))),
+        Some(key) => {
+            let ciphertext = fs::read(path)?;
+            let plaintext = crypto::decrypt(key, &ciphertext)?;
+            Ok(csv::Reader::from_reader(Box::new(io::Cursor::new(
+                plaintext,
+            ))))
+        }
+    }
+}
+
+/// Totals printed at the end of a run, and used to decide the exit code.
+#[derive(Debug, Default)]
+struct RunReport {
+    processed: u64,
+    new_defaults: u64,
+    added_to_hlr: u64,
+    skipped_existing: u64,
+    failed: u64,
+}
+
+impl RunReport {
+    fn print_summary(&self) {
+        println!("[+] run summary:");
+        println!("      processed:          {}", self.processed);
+        println!("      new defaults:       {}", self.new_defaults);
+        println!("      added to HLR:       {}", self.added_to_hlr);
+        println!("      skipped (existing): {}", self.skipped_existing);
+        println!("      failed:             {}", self.failed);
+    }
+}
+
+/// Log a rejected record to stderr and, if `rejects` is set, to the rejects
+/// file. When `continue_on_error` is false this is fatal and the process
+/// exits immediately, matching the old `exit(2/3/4)` behaviour.
+fn reject_record(
+    rejects: &mut Option<csv::Writer<File>>,
+    continue_on_error: bool,
+    file: &str,
+    line: Option<u64>,
+    imsi: Option<&str>,
+    reason: &str,
+) {
+    let line = line.map(|l| l.to_string()).unwrap_or_default();
+    let imsi = imsi.unwrap_or("");
+    eprintln!("[!] rejected record in {file}:{line} (imsi {imsi}): {reason}");
+
+    if let Some(w) = rejects {
+        w.write_record([file, &line, imsi, reason])
+            .and_then(|()| w.flush())
+            .expect("failed to write to rejects file!");
+    }
+
+    if !continue_on_error {
+        eprintln!(
+            "fatal: aborting on first error (pass --continue-on-error to skip and keep going)"
+        );
+        std::process::exit(2);
+    }
+}
+
+/// Remove a bare boolean `flag` from `args` if present, returning whether it
+/// was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove a `flag value` pair from `args` if present, returning the value.
+/// `Err` means `flag` was given but with no value following it (e.g. it was
+/// the last argument) — a usage error, not an absent flag.
+fn take_value_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) if i + 1 < args.len() => Ok(Some(args.drain(i..i + 2).nth(1).unwrap())),
+        Some(_) => Err(format!("{flag} requires a value")),
+        None => Ok(None),
+    }
+}
+
+/// A [`KeysRecord`] with its default MSISDN already resolved, queued up to
+/// be flushed as part of a `--batch-size` batch. The `KeysRecord` inside
+/// keeps owning (and, on drop, zeroizing) the KI/OPC secrets right up until
+/// the batch is sent to the HLR.
+struct PendingRecord {
+    imsi: String,
+    default_msisdn: u64,
+    record: KeysRecord,
+    line: Option<u64>,
+}
+
+/// Send everything queued up in `pending` to the HLR as one batch, then
+/// route each outcome into `report`/`rejects`. Every record goes through
+/// here, including with the default `--batch-size` of 1.
+fn flush_batch(
+    hlr: &mut HlrSession,
+    pending: &mut Vec<PendingRecord>,
+    report: &mut RunReport,
+    rejects: &mut Option<csv::Writer<File>>,
+    continue_on_error: bool,
+    file: &str,
+) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let batch: Vec<PendingImport<'_>> = pending
+        .iter()
+        .map(|p| PendingImport {
+            imsi: &p.imsi,
+            default_msisdn: p.default_msisdn,
+            ki: &p.record.ki,
+            opc: &p.record.opc,
+        })
+        .collect();
+    let outcomes = hlr.import_batch(&batch);
+
+    for (p, outcome) in pending.drain(..).zip(outcomes) {
+        match outcome {
+            Ok(RecordOutcome::Created) => report.added_to_hlr += 1,
+            Ok(RecordOutcome::AlreadyPresent) => report.skipped_existing += 1,
+            Err(e) => {
+                reject_record(
+                    rejects,
+                    continue_on_error,
+                    file,
+                    p.line,
+                    Some(&p.imsi),
+                    &e.to_string(),
+                );
+                report.failed += 1;
+            }
+        }
+    }
+}
+
+fn usage_and_exit(our_bin: &str) -> ! {
+    eprintln!("usage: {our_bin} [--hlr-address addr] [--backoff-ms ms] [--max-backoff-ms ms] [--max-retries n] [--encrypted-input] [--encrypt-defaults] [--continue-on-error] [--batch-size n] file_to_import.csv [additional_files...]");
+    std::process::exit(1);
+}
+
+/// Print a fatal startup error (no input has been processed yet, so there's
+/// nothing for `--continue-on-error`/the rejects file to do here) and exit
+/// non-zero, in place of unwrapping it back into a panic.
+fn die(msg: impl std::fmt::Display) -> ! {
+    eprintln!("fatal: {msg}");
+    std::process::exit(2);
 }
 
 fn main() {
     let mut args = std::env::args();
     let our_bin = args.next().unwrap();
-    let Some(first_file) = args.next() else {
-        eprintln!("usage: {our_bin} file_to_import.csv [additional_files...]");
-        std::process::exit(1);
+    let mut rest = args.collect::<Vec<_>>();
+    let vty_config = VtyConfig::from_args_and_env(&mut rest);
+    let encryption = EncryptionOptions::from_args(&mut rest);
+    let continue_on_error = take_flag(&mut rest, "--continue-on-error");
+    let batch_size = match take_value_flag(&mut rest, "--batch-size") {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("fatal: {e}");
+            usage_and_exit(&our_bin);
+        }
+    }
+    .map(|v| v.parse().unwrap_or_else(|_| usage_and_exit(&our_bin)))
+    .unwrap_or(1usize)
+    .max(1);
+
+    let key = if encryption.any() {
+        Some(crypto::load_key().unwrap_or_else(|e| die(e)))
+    } else {
+        None
     };
-    let mut all_files = args.collect::<Vec<_>>();
+
+    let mut rest = rest.into_iter();
+    let Some(first_file) = rest.next() else {
+        usage_and_exit(&our_bin);
+    };
+    let mut all_files = rest.collect::<Vec<_>>();
     all_files.insert(0, first_file);
 
     println!("[+] using default msisdn csv at {DEFAULTS_PATH}");
-    let mut defaults = OpenOptions::new()
+    let defaults_key = key.as_deref().filter(|_| encryption.encrypt_defaults);
+
+    // Both modes open the file read+append, but in encrypted mode nothing is
+    // written through this handle, since every new default rewrites the
+    // whole ciphertext via `write_encrypted_defaults`; the plain path appends
+    // new defaults to it.
+    let mut defaults_file = OpenOptions::new()
         .read(true)
         .append(true)
         .open(DEFAULTS_PATH)
         .unwrap();
-    let (imsis, mut latest_default) = read_defaults(&mut defaults).unwrap();
-    defaults.seek(SeekFrom::End(0)).expect("seek failed");
-    println!("[+] {} MSISDNs in database; last was {latest_default}", imsis.len());
-
-    println!("[+] connecting to HLR");
-    let tcp_hlr = TcpStream::connect(OSMO_HLR_ADDRESS).unwrap();
-    let tcp_hlr_clone = tcp_hlr.try_clone().unwrap();
-    let mut hlr = rexpect::session::spawn_stream(tcp_hlr, tcp_hlr_clone, Some(1500));
-    hlr.exp_string("OsmoHLR> ").unwrap();
-    hlr.send_line("enable").unwrap();
-    hlr.exp_string("OsmoHLR# ").unwrap();
+    let (mut imsis, mut latest_default) =
+        read_defaults(&mut defaults_file, defaults_key).unwrap_or_else(|e| die(e));
+    defaults_file.seek(SeekFrom::End(0)).expect("seek failed");
+    println!(
+        "[+] {} MSISDNs in database; last was {latest_default}",
+        imsis.len()
+    );
+
+    println!("[+] connecting to HLR at {}", vty_config.address);
+    let mut hlr = HlrSession::connect(vty_config).unwrap_or_else(|e| die(e));
 
     println!("[+] importing {} files", all_files.len());
+    if continue_on_error {
+        println!("[+] --continue-on-error set; rejected records go to {REJECTS_PATH}");
+    }
+    if batch_size > 1 {
+        println!("[+] pipelining HLR commands in batches of {batch_size}");
+    }
 
-    let mut cnt_new_default = 0;
-    let mut cnt_new_hlr = 0;
+    let mut rejects = continue_on_error.then(|| {
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(REJECTS_PATH)
+            .unwrap();
+        let needs_header = f.metadata().unwrap().len() == 0;
+        let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(f);
+        if needs_header {
+            w.write_record(["File", "Line", "IMSI", "Reason"]).unwrap();
+            w.flush().unwrap();
+        }
+        w
+    });
+
+    let mut report = RunReport::default();
 
     for file in all_files {
         println!("[+] importing {file}...");
-        let mut reader = open_csv(&file).unwrap();
-        for res in reader.deserialize() {
-            let record: KeysRecord = match res {
+        let input_key = key.as_deref().filter(|_| encryption.encrypted_input);
+        let mut reader = match open_keys_csv(&file, input_key) {
+            Ok(r) => r,
+            Err(e) => {
+                reject_record(
+                    &mut rejects,
+                    continue_on_error,
+                    &file,
+                    None,
+                    None,
+                    &format!("could not open input file: {e}"),
+                );
+                report.failed += 1;
+                continue;
+            }
+        };
+        let headers = match reader.headers() {
+            Ok(h) => h.clone(),
+            Err(e) => {
+                reject_record(
+                    &mut rejects,
+                    continue_on_error,
+                    &file,
+                    None,
+                    None,
+                    &format!("could not read CSV headers: {e}"),
+                );
+                report.failed += 1;
+                continue;
+            }
+        };
+        let mut pending: Vec<PendingRecord> = Vec::with_capacity(batch_size);
+
+        for string_record in reader.records() {
+            report.processed += 1;
+
+            let string_record = match string_record {
+                Ok(r) => r,
+                Err(e) => {
+                    reject_record(
+                        &mut rejects,
+                        continue_on_error,
+                        &file,
+                        None,
+                        None,
+                        &e.to_string(),
+                    );
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            let line = string_record.position().map(|p| p.line());
+
+            let record: KeysRecord = match string_record.deserialize(Some(&headers)) {
                 Ok(v) => v,
                 Err(e) => {
-                    eprintln!("fatal: could not deserialize record");
-                    eprintln!("       in file {file}");
-                    eprintln!("       error: {e}");
-                    std::process::exit(2);
+                    reject_record(
+                        &mut rejects,
+                        continue_on_error,
+                        &file,
+                        line,
+                        None,
+                        &e.to_string(),
+                    );
+                    report.failed += 1;
+                    continue;
                 }
             };
 
-            let imsi = record.imsi;
+            let imsi = record.imsi.clone();
 
             let default_msisdn = if let Some(d) = imsis.get(&imsi) {
                 // Don't make a new default MSISDN if we already have one.
@@ -109,45 +427,67 @@ fn main() {
                 latest_default += 1;
                 assert!(latest_default < 90500000);
                 let ret = latest_default;
+                imsis.insert(imsi.clone(), ret);
 
-                write!(defaults, "{imsi},{ret}\n")
-                    .expect("failed to write to defaults file!");
-                
-                cnt_new_default += 1;
+                let write_result = if encryption.encrypt_defaults {
+                    write_encrypted_defaults(DEFAULTS_PATH, defaults_key.unwrap(), &imsis)
+                } else {
+                    write!(defaults_file, "{imsi},{ret}\n")
+                };
 
-                ret
-            };
-
-            hlr.send_line(&format!("subscriber imsi {imsi} show")).unwrap();
-            let show_res = hlr.exp_string("OsmoHLR# ").unwrap();
-            let show_res_first = show_res.lines().nth(1).unwrap();
+                if let Err(e) = write_result {
+                    reject_record(
+                        &mut rejects,
+                        continue_on_error,
+                        &file,
+                        line,
+                        Some(&imsi),
+                        &format!("failed to write defaults file: {e}"),
+                    );
+                    report.failed += 1;
+                    continue;
+                }
 
-            if !show_res_first.starts_with("% No subscriber") {
-                // Already did this one!
-                continue;
-            }
+                report.new_defaults += 1;
 
-            let mut expect_result = |line: String, wanted: &str| {
-                hlr.send_line(&line).unwrap();
-                let full_res = hlr.exp_string("OsmoHLR# ").unwrap();
-                // The first line is what we echoed back, so we need to strip it
-                let first_newline = full_res.find('\n').unwrap();
-                let res = &full_res[first_newline+1..];
-                if !res.starts_with(wanted) || (wanted.is_empty() && !res.is_empty()) {
-                    eprintln!("fatal: weird HLR response for {imsi}");
-                    eprintln!("{full_res}");
-                    std::process::exit(4);
-                }
+                ret
             };
 
-            expect_result(format!("subscriber imsi {imsi} create"), "% Created subscriber");
-            expect_result(format!("subscriber imsi {imsi} update msisdn {default_msisdn}"), "% Updated subscriber");
-            expect_result(format!("subscriber imsi {imsi} update aud3g milenage k {} opc {}", record.ki, record.opc), "");
-            cnt_new_hlr += 1;
+            pending.push(PendingRecord {
+                imsi,
+                default_msisdn,
+                record,
+                line,
+            });
+            if pending.len() >= batch_size {
+                flush_batch(
+                    &mut hlr,
+                    &mut pending,
+                    &mut report,
+                    &mut rejects,
+                    continue_on_error,
+                    &file,
+                );
+            }
         }
+
+        flush_batch(
+            &mut hlr,
+            &mut pending,
+            &mut report,
+            &mut rejects,
+            continue_on_error,
+            &file,
+        );
     }
 
-    defaults.sync_all().unwrap();
+    defaults_file.sync_all().unwrap();
+    if let Some(w) = rejects.as_mut() {
+        w.flush().unwrap();
+    }
 
-    println!("[+] {cnt_new_default} new defaults, {cnt_new_hlr} added to HLR");
+    report.print_summary();
+    if report.failed > 0 {
+        std::process::exit(2);
+    }
 }
@@ -0,0 +1,307 @@
+use std::io;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use rexpect::session::StreamSession;
+
+/// Connection parameters for the OsmoHLR VTY session, tunable via CLI flags
+/// or environment variables so operators don't need to edit the binary to
+/// point it at a different HLR or tune reconnect behaviour.
+#[derive(Debug, Clone)]
+pub struct VtyConfig {
+    pub address: String,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_retries: u32,
+}
+
+impl Default for VtyConfig {
+    fn default() -> Self {
+        VtyConfig {
+            address: "127.0.0.1:4258".to_string(),
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            max_retries: 10,
+        }
+    }
+}
+
+impl VtyConfig {
+    /// Build a config from `--hlr-address`/`--backoff-ms`/`--max-backoff-ms`/
+    /// `--max-retries` flags (consumed out of `args`), falling back to the
+    /// `HLR_ADDRESS`/`HLR_BACKOFF_MS`/`HLR_MAX_BACKOFF_MS`/`HLR_MAX_RETRIES`
+    /// env vars, and finally to `VtyConfig::default()`.
+    ///
+    /// Any arg not recognised as one of these flags is left in `args` for the
+    /// caller to interpret (e.g. as an input file).
+    pub fn from_args_and_env(args: &mut Vec<String>) -> Self {
+        let mut config = VtyConfig::default();
+
+        if let Ok(v) = std::env::var("HLR_ADDRESS") {
+            config.address = v;
+        }
+        if let Ok(v) = std::env::var("HLR_BACKOFF_MS") {
+            if let Ok(ms) = v.parse() {
+                config.initial_backoff = Duration::from_millis(ms);
+            }
+        }
+        if let Ok(v) = std::env::var("HLR_MAX_BACKOFF_MS") {
+            if let Ok(ms) = v.parse() {
+                config.max_backoff = Duration::from_millis(ms);
+            }
+        }
+        if let Ok(v) = std::env::var("HLR_MAX_RETRIES") {
+            if let Ok(n) = v.parse() {
+                config.max_retries = n;
+            }
+        }
+
+        let mut i = 0;
+        while i < args.len() {
+            let consumed = match args[i].as_str() {
+                "--hlr-address" if i + 1 < args.len() => {
+                    config.address = args[i + 1].clone();
+                    2
+                }
+                "--backoff-ms" if i + 1 < args.len() => {
+                    if let Ok(ms) = args[i + 1].parse() {
+                        config.initial_backoff = Duration::from_millis(ms);
+                    }
+                    2
+                }
+                "--max-backoff-ms" if i + 1 < args.len() => {
+                    if let Ok(ms) = args[i + 1].parse() {
+                        config.max_backoff = Duration::from_millis(ms);
+                    }
+                    2
+                }
+                "--max-retries" if i + 1 < args.len() => {
+                    if let Ok(n) = args[i + 1].parse() {
+                        config.max_retries = n;
+                    }
+                    2
+                }
+                _ => 0,
+            };
+
+            if consumed > 0 {
+                args.drain(i..i + consumed);
+            } else {
+                i += 1;
+            }
+        }
+
+        config
+    }
+}
+
+static PROMPT: &str = "OsmoHLR# ";
+
+/// The fields of a subscriber as reported by `subscriber imsi <x> show`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriberInfo {
+    pub imsi: String,
+    pub msisdn: Option<String>,
+    pub auth_algo: Option<String>,
+}
+
+/// A classified OsmoHLR VTY response, in place of matching raw strings
+/// against `"% Created subscriber"`-style prefixes at every call site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VtyResponse {
+    Created,
+    Updated,
+    AlreadyExists,
+    NoSubscriber,
+    Shown(SubscriberInfo),
+    /// The command produced no `% ...` status line and no subscriber dump
+    /// (e.g. `update aud3g`, which is silent on success).
+    Ok,
+    Error(String),
+}
+
+/// Parse the text returned between sending a command and the next prompt:
+/// strip the echoed command line and the trailing prompt, then classify
+/// what's left.
+fn parse_response(raw: &str) -> VtyResponse {
+    let body = raw.strip_suffix(PROMPT).unwrap_or(raw);
+    let body = match body.find('\n') {
+        Some(idx) => &body[idx + 1..],
+        None => "",
+    };
+    let body = body.trim_end_matches('\n');
+
+    if body.is_empty() {
+        return VtyResponse::Ok;
+    }
+
+    let first_line = body.lines().next().unwrap_or("");
+    if let Some(status) = first_line.strip_prefix("% ") {
+        return match status {
+            s if s.starts_with("Created subscriber") => VtyResponse::Created,
+            s if s.starts_with("Updated subscriber") => VtyResponse::Updated,
+            s if s.starts_with("No subscriber") => VtyResponse::NoSubscriber,
+            s if s.starts_with("Subscriber already") => VtyResponse::AlreadyExists,
+            s => VtyResponse::Error(s.to_string()),
+        };
+    }
+
+    VtyResponse::Shown(parse_subscriber_info(body))
+}
+
+/// Parse the `key: value` lines of a `subscriber imsi <x> show` dump.
+fn parse_subscriber_info(body: &str) -> SubscriberInfo {
+    let mut imsi = String::new();
+    let mut msisdn = None;
+    let mut auth_algo = None;
+
+    for line in body.lines() {
+        let line = line.trim();
+        if let Some(v) = line.strip_prefix("IMSI:") {
+            imsi = v.trim().to_string();
+        } else if let Some(v) = line.strip_prefix("MSISDN:") {
+            let v = v.trim();
+            if !v.is_empty() && v != "None" {
+                msisdn = Some(v.to_string());
+            }
+        } else if let Some(v) = line.strip_prefix("3G Authentication:") {
+            let v = v.trim();
+            if !v.is_empty() && v != "NONE" {
+                auth_algo = Some(v.to_string());
+            }
+        }
+    }
+
+    SubscriberInfo {
+        imsi,
+        msisdn,
+        auth_algo,
+    }
+}
+
+type Session = StreamSession<TcpStream, TcpStream>;
+
+/// A VTY session to OsmoHLR that reconnects and replays the `enable`
+/// handshake on its own whenever the underlying TCP stream drops, instead of
+/// letting a flaky connection abort a multi-thousand-record import.
+pub struct HlrSession {
+    config: VtyConfig,
+    session: Session,
+}
+
+impl HlrSession {
+    /// Connect to the HLR, retrying with exponential backoff up to
+    /// `config.max_retries` times. Returns the last error if the budget is
+    /// exhausted.
+    pub fn connect(config: VtyConfig) -> io::Result<Self> {
+        let session = Self::connect_and_handshake(&config)?;
+        Ok(HlrSession { config, session })
+    }
+
+    fn connect_and_handshake(config: &VtyConfig) -> io::Result<Session> {
+        let mut backoff = config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match Self::try_connect_and_handshake(&config.address) {
+                Ok(session) => return Ok(session),
+                Err(e) => {
+                    attempt += 1;
+                    if attempt > config.max_retries {
+                        return Err(e);
+                    }
+                    eprintln!(
+                        "[!] HLR connection attempt {attempt}/{} failed ({e}); retrying in {:?}",
+                        config.max_retries, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, config.max_backoff);
+                }
+            }
+        }
+    }
+
+    fn try_connect_and_handshake(address: &str) -> io::Result<Session> {
+        let tcp = TcpStream::connect(address)?;
+        let tcp_clone = tcp.try_clone()?;
+        let mut session = rexpect::session::spawn_stream(tcp, tcp_clone, Some(1500));
+        session.exp_string("OsmoHLR> ").map_err(rexpect_err)?;
+        session.send_line("enable").map_err(rexpect_err)?;
+        session.exp_string(PROMPT).map_err(rexpect_err)?;
+        Ok(session)
+    }
+
+    /// Re-establish the TCP connection and `enable` handshake in place,
+    /// using the same backoff/retry budget as the initial connect.
+    fn reconnect(&mut self) -> io::Result<()> {
+        eprintln!("[!] reconnecting to HLR at {}", self.config.address);
+        self.session = Self::connect_and_handshake(&self.config)?;
+        Ok(())
+    }
+
+    /// Send a command and wait for the next `OsmoHLR# ` prompt, returning the
+    /// raw response text. If the send or the read fails (e.g. because the
+    /// HLR dropped the connection), transparently reconnects and resends the
+    /// same command rather than propagating the failure to the caller.
+    pub fn command(&mut self, line: &str) -> io::Result<String> {
+        match self.try_command(line) {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                self.reconnect()?;
+                self.try_command(line)
+            }
+        }
+    }
+
+    /// Like [`Self::command`], but classifies the response instead of
+    /// returning it raw.
+    pub fn command_parsed(&mut self, line: &str) -> io::Result<VtyResponse> {
+        self.command(line).map(|raw| parse_response(&raw))
+    }
+
+    fn try_command(&mut self, line: &str) -> io::Result<String> {
+        self.session.send_line(line).map_err(rexpect_err)?;
+        self.session.exp_string(PROMPT).map_err(rexpect_err)
+    }
+
+    /// Send `lines` one after another without waiting for a response in
+    /// between, then read back `lines.len()` prompt-delimited blocks,
+    /// correlating each one to the command that produced it by the order the
+    /// prompts arrive in. This pipelines the request/response round-trips
+    /// instead of paying a full network RTT per command, which matters once
+    /// an import runs to thousands of SIMs. As with [`Self::command`], a
+    /// failure anywhere in the batch reconnects and resends the whole batch
+    /// rather than trying to figure out which commands already landed.
+    pub fn command_batch(&mut self, lines: &[String]) -> io::Result<Vec<String>> {
+        match self.try_command_batch(lines) {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                self.reconnect()?;
+                self.try_command_batch(lines)
+            }
+        }
+    }
+
+    /// Like [`Self::command_batch`], but classifies each response instead of
+    /// returning it raw.
+    pub fn command_batch_parsed(&mut self, lines: &[String]) -> io::Result<Vec<VtyResponse>> {
+        self.command_batch(lines)
+            .map(|raws| raws.iter().map(|raw| parse_response(raw)).collect())
+    }
+
+    fn try_command_batch(&mut self, lines: &[String]) -> io::Result<Vec<String>> {
+        for line in lines {
+            self.session.send_line(line).map_err(rexpect_err)?;
+        }
+
+        let mut responses = Vec::with_capacity(lines.len());
+        for _ in lines {
+            responses.push(self.session.exp_string(PROMPT).map_err(rexpect_err)?);
+        }
+        Ok(responses)
+    }
+}
+
+fn rexpect_err(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}